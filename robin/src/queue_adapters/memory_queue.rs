@@ -0,0 +1,293 @@
+use error::*;
+use super::{unix_timestamp, DequeueTimeout, EnqueuedJob, JobQueue, NoJobDequeued, QueueIdentifier};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::default::Default;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A `Mutex` guarding `State` was poisoned, i.e. some other thread panicked while holding the
+/// lock. Not a Redis error, so it gets its own message instead of reusing
+/// `Error::UnknownRedisError`.
+fn lock_poisoned() -> Error {
+    Error::Internal("Lock was poisoned".to_string())
+}
+
+#[derive(Default)]
+struct State {
+    main: VecDeque<EnqueuedJob>,
+    retry: VecDeque<EnqueuedJob>,
+    processing: HashMap<String, VecDeque<EnqueuedJob>>,
+    heartbeats: HashMap<String, u64>,
+    /// Every `worker_id` that has ever dequeued a job, so `reap` can find a worker's orphaned
+    /// processing list even if it crashed before calling `heartbeat` even once.
+    workers: HashSet<String>,
+    schedule: BTreeMap<u64, Vec<EnqueuedJob>>,
+}
+
+impl State {
+    fn list_mut(&mut self, iden: QueueIdentifier) -> &mut VecDeque<EnqueuedJob> {
+        match iden {
+            QueueIdentifier::Main => &mut self.main,
+            QueueIdentifier::Retry => &mut self.retry,
+        }
+    }
+
+    fn processing_mut(&mut self, iden: QueueIdentifier, worker_id: &str) -> &mut VecDeque<EnqueuedJob> {
+        self.processing
+            .entry(format!("{}_{}", iden.redis_queue_name(), worker_id))
+            .or_insert_with(VecDeque::new)
+    }
+}
+
+/// An in-memory `JobQueue` backend. It mirrors `RedisQueue`'s blocking `dequeue` semantics with a
+/// condvar instead of `BLPOP`/`BRPOPLPUSH`, so downstream crates can unit-test job handlers
+/// against the same code paths without a running Redis server.
+#[derive(Clone)]
+pub struct MemoryQueue {
+    inner: Arc<(Mutex<State>, Condvar)>,
+}
+
+/// The configuration required to create a `MemoryQueue`. There's nothing to configure since
+/// there's no connection to open.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct MemoryConfig;
+
+impl JobQueue for MemoryQueue {
+    type Config = MemoryConfig;
+
+    /// Create a new, empty `MemoryQueue`.
+    fn new(_init: &MemoryConfig) -> RobinResult<Self> {
+        Ok(MemoryQueue {
+            inner: Arc::new((Mutex::new(State::default()), Condvar::new())),
+        })
+    }
+
+    /// Put a job into a queue.
+    fn enqueue(&self, enq_job: EnqueuedJob, iden: QueueIdentifier) -> RobinResult<()> {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut state = lock.lock().map_err(|_| lock_poisoned())?;
+        state.list_mut(iden).push_back(enq_job);
+        cvar.notify_all();
+        Ok(())
+    }
+
+    /// Pull a job out of the queue, blocking for `timeout` seconds if it's empty. The job is
+    /// moved into `worker_id`'s processing list until `ack` is called.
+    fn dequeue(
+        &self,
+        timeout: &DequeueTimeout,
+        iden: QueueIdentifier,
+        worker_id: &str,
+    ) -> Result<EnqueuedJob, NoJobDequeued> {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut state = lock
+            .lock()
+            .map_err(|_| NoJobDequeued::from(lock_poisoned()))?;
+        let budget = Duration::from_secs(u64::from(timeout.0));
+        let start = Instant::now();
+
+        loop {
+            if let Some(enq_job) = state.list_mut(iden).pop_front() {
+                state.processing_mut(iden, worker_id).push_back(enq_job.clone());
+
+                // Seed the worker's membership and heartbeat right away, so `reap` can find this
+                // job's processing list even if the worker crashes before its first explicit
+                // `heartbeat()` call.
+                state.workers.insert(worker_id.to_string());
+                let now = unix_timestamp(SystemTime::now())?;
+                state.heartbeats.insert(worker_id.to_string(), now);
+
+                return Ok(enq_job);
+            }
+
+            let remaining = match budget.checked_sub(start.elapsed()) {
+                Some(remaining) if remaining > Duration::new(0, 0) => remaining,
+                _ => return Err(NoJobDequeued::BecauseTimeout),
+            };
+
+            let (guard, _) = cvar
+                .wait_timeout(state, remaining)
+                .map_err(|_| NoJobDequeued::from(lock_poisoned()))?;
+            state = guard;
+        }
+    }
+
+    /// Acknowledge that `enq_job` finished processing, removing it from `worker_id`'s processing
+    /// list.
+    fn ack(&self, enq_job: &EnqueuedJob, iden: QueueIdentifier, worker_id: &str) -> RobinResult<()> {
+        let &(ref lock, _) = &*self.inner;
+        let mut state = lock.lock().map_err(|_| lock_poisoned())?;
+        let processing = state.processing_mut(iden, worker_id);
+        if let Some(pos) = processing.iter().position(|job| job == enq_job) {
+            processing.remove(pos);
+        }
+        Ok(())
+    }
+
+    /// Record that `worker_id` is still alive.
+    fn heartbeat(&self, worker_id: &str) -> RobinResult<()> {
+        let &(ref lock, _) = &*self.inner;
+        let mut state = lock.lock().map_err(|_| lock_poisoned())?;
+        let now = unix_timestamp(SystemTime::now())?;
+        state.heartbeats.insert(worker_id.to_string(), now);
+        Ok(())
+    }
+
+    /// Requeue jobs belonging to any worker whose heartbeat is older than `timeout` (or that has
+    /// never heartbeated at all).
+    ///
+    /// The heartbeat and worker-membership bookkeeping is shared across every `QueueIdentifier`,
+    /// since a single worker can dequeue from both `Main` and `Retry`. So a worker is only
+    /// forgotten here once *every* queue's processing list for it is empty; otherwise a job
+    /// still in-flight on a queue nobody has called `reap` for yet would be orphaned the moment
+    /// its heartbeat/worker entry disappears.
+    fn reap(&self, iden: QueueIdentifier, timeout: Duration) -> RobinResult<usize> {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut state = lock.lock().map_err(|_| lock_poisoned())?;
+        let now = unix_timestamp(SystemTime::now())?;
+
+        // Every worker that has ever dequeued a job is in `workers`, regardless of whether it
+        // survived long enough to call `heartbeat`, so a worker that crashed immediately after
+        // `dequeue` still has its processing list found and drained here.
+        let dead_workers: Vec<String> = state
+            .workers
+            .iter()
+            .filter(|worker_id| {
+                let last_seen = state.heartbeats.get(*worker_id).cloned().unwrap_or(0);
+                now.saturating_sub(last_seen) >= timeout.as_secs()
+            })
+            .cloned()
+            .collect();
+
+        let mut requeued = 0;
+        for worker_id in dead_workers {
+            let jobs: Vec<EnqueuedJob> = state
+                .processing_mut(iden, &worker_id)
+                .drain(..)
+                .collect();
+            requeued += jobs.len();
+            state.list_mut(iden).extend(jobs);
+
+            let still_in_flight = QueueIdentifier::all()
+                .iter()
+                .any(|other| !state.processing_mut(*other, &worker_id).is_empty());
+            if !still_in_flight {
+                state.heartbeats.remove(&worker_id);
+                state.workers.remove(&worker_id);
+            }
+        }
+
+        if requeued > 0 {
+            cvar.notify_all();
+        }
+
+        Ok(requeued)
+    }
+
+    /// Schedule a job to be moved onto the main queue at `when`.
+    fn enqueue_at(&self, enq_job: EnqueuedJob, when: SystemTime) -> RobinResult<()> {
+        let &(ref lock, _) = &*self.inner;
+        let mut state = lock.lock().map_err(|_| lock_poisoned())?;
+        let score = unix_timestamp(when)?;
+        state.schedule.entry(score).or_insert_with(Vec::new).push(enq_job);
+        Ok(())
+    }
+
+    /// Move every scheduled job whose time has come onto the main queue.
+    fn enqueue_due_jobs(&self, now: SystemTime) -> RobinResult<usize> {
+        let &(ref lock, ref cvar) = &*self.inner;
+        let mut state = lock.lock().map_err(|_| lock_poisoned())?;
+        let now = unix_timestamp(now)?;
+
+        let still_future = state.schedule.split_off(&(now + 1));
+        let due = ::std::mem::replace(&mut state.schedule, still_future);
+
+        let mut moved = 0;
+        for (_, jobs) in due {
+            moved += jobs.len();
+            state.main.extend(jobs);
+        }
+
+        if moved > 0 {
+            cvar.notify_all();
+        }
+
+        Ok(moved)
+    }
+
+    /// Delete everything in the queue.
+    fn delete_all(&self, iden: QueueIdentifier) -> RobinResult<()> {
+        let &(ref lock, _) = &*self.inner;
+        let mut state = lock.lock().map_err(|_| lock_poisoned())?;
+        state.list_mut(iden).clear();
+        Ok(())
+    }
+
+    /// The number of jobs in the queue.
+    fn size(&self, iden: QueueIdentifier) -> RobinResult<usize> {
+        let &(ref lock, _) = &*self.inner;
+        let mut state = lock.lock().map_err(|_| lock_poisoned())?;
+        Ok(state.list_mut(iden).len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{EnqueuedJobBuilder, RetryCount};
+
+    fn job(name: &str) -> EnqueuedJob {
+        EnqueuedJobBuilder::default()
+            .name(name.to_string())
+            .args("{}".to_string())
+            .retry_count(RetryCount::NeverRetried)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn enqueue_dequeue_ack_roundtrip() {
+        let queue = MemoryQueue::new(&MemoryConfig::default()).unwrap();
+        queue.enqueue(job("send_email"), QueueIdentifier::Main).unwrap();
+        assert_eq!(queue.size(QueueIdentifier::Main).unwrap(), 1);
+
+        let dequeued = queue
+            .dequeue(&DequeueTimeout(1), QueueIdentifier::Main, "worker-1")
+            .unwrap();
+        assert_eq!(dequeued.name(), "send_email");
+        assert_eq!(queue.size(QueueIdentifier::Main).unwrap(), 0);
+
+        queue.ack(&dequeued, QueueIdentifier::Main, "worker-1").unwrap();
+        assert_eq!(
+            queue.reap(QueueIdentifier::Main, Duration::from_secs(0)).unwrap(),
+            0,
+            "an acked job shouldn't be sitting in a processing list anymore"
+        );
+    }
+
+    #[test]
+    fn dequeue_times_out_when_empty() {
+        let queue = MemoryQueue::new(&MemoryConfig::default()).unwrap();
+
+        match queue.dequeue(&DequeueTimeout(1), QueueIdentifier::Main, "worker-1") {
+            Err(NoJobDequeued::BecauseTimeout) => {}
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reap_requeues_jobs_left_behind_by_a_dead_worker() {
+        let queue = MemoryQueue::new(&MemoryConfig::default()).unwrap();
+        queue.enqueue(job("send_email"), QueueIdentifier::Main).unwrap();
+
+        queue
+            .dequeue(&DequeueTimeout(1), QueueIdentifier::Main, "worker-1")
+            .unwrap();
+        assert_eq!(queue.size(QueueIdentifier::Main).unwrap(), 0);
+
+        // "worker-1" never acks or heartbeats again, as if it crashed right after dequeueing.
+        let requeued = queue.reap(QueueIdentifier::Main, Duration::from_secs(0)).unwrap();
+        assert_eq!(requeued, 1);
+        assert_eq!(queue.size(QueueIdentifier::Main).unwrap(), 1);
+    }
+}
@@ -0,0 +1,166 @@
+use error::*;
+use config::Config;
+use super::{EnqueuedJob, JobQueue, QueueIdentifier};
+
+/// The rest of the middleware chain, to be called zero or more times.
+pub type Next<'a> = &'a Fn(EnqueuedJob, QueueIdentifier) -> RobinResult<()>;
+
+/// A hook that wraps job processing end to end. Implementors can run code before and after
+/// `next`, inspect or replace its result, call it more than once, or skip it entirely, which
+/// makes `MiddleWare` the extension point for cross-cutting behavior like logging, timing, or
+/// retry policy.
+pub trait MiddleWare<Q: JobQueue> {
+    /// Handle a single job. Call `next` to continue down the chain to the next middleware (or,
+    /// for the innermost middleware, the job itself).
+    fn call(
+        &self,
+        enq_job: EnqueuedJob,
+        iden: QueueIdentifier,
+        queue: &Q,
+        next: Next,
+    ) -> RobinResult<()>;
+}
+
+/// Fold a list of middleware and the core job-processing closure into a single composed closure,
+/// with the first middleware in `middlewares` running outermost.
+pub fn compose<'a, Q: JobQueue>(
+    middlewares: &'a [Box<MiddleWare<Q> + 'a>],
+    queue: &'a Q,
+    core: Box<Fn(EnqueuedJob, QueueIdentifier) -> RobinResult<()> + 'a>,
+) -> Box<Fn(EnqueuedJob, QueueIdentifier) -> RobinResult<()> + 'a> {
+    middlewares
+        .iter()
+        .rev()
+        .fold(core, |next, middleware| {
+            Box::new(move |enq_job, iden| middleware.call(enq_job, iden, queue, &*next))
+        })
+}
+
+/// Retries a failed job by incrementing its `RetryCount` and re-enqueueing it onto
+/// `QueueIdentifier::Retry`, instead of letting the failure propagate to the caller. Once the
+/// job's retry limit has been reached, the error is returned to the caller unchanged.
+pub struct RetryMiddleware {
+    config: Config,
+}
+
+impl RetryMiddleware {
+    /// Create a new `RetryMiddleware` using the retry limit from `config`.
+    pub fn new(config: Config) -> RetryMiddleware {
+        RetryMiddleware { config }
+    }
+}
+
+impl<Q: JobQueue> MiddleWare<Q> for RetryMiddleware {
+    fn call(
+        &self,
+        enq_job: EnqueuedJob,
+        iden: QueueIdentifier,
+        queue: &Q,
+        next: Next,
+    ) -> RobinResult<()> {
+        match next(enq_job.clone(), iden) {
+            Ok(()) => Ok(()),
+
+            Err(error) => {
+                if enq_job.retry_count().limit_reached(&self.config) {
+                    return Err(error);
+                }
+
+                queue.enqueue(enq_job.retried(), QueueIdentifier::Retry)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory_queue::{MemoryConfig, MemoryQueue};
+    use super::super::{EnqueuedJobBuilder, RetryCount};
+    use std::sync::{Arc, Mutex};
+
+    fn job() -> EnqueuedJob {
+        EnqueuedJobBuilder::default()
+            .name("send_email".to_string())
+            .args("{}".to_string())
+            .retry_count(RetryCount::NeverRetried)
+            .build()
+            .unwrap()
+    }
+
+    fn config(retry_count_limit: u32) -> Config {
+        Config {
+            retry_count_limit,
+            ..Config::default()
+        }
+    }
+
+    struct RecordingMiddleware {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl<Q: JobQueue> MiddleWare<Q> for RecordingMiddleware {
+        fn call(&self, enq_job: EnqueuedJob, iden: QueueIdentifier, _queue: &Q, next: Next) -> RobinResult<()> {
+            self.log.lock().unwrap().push(format!("{}:before", self.name));
+            let result = next(enq_job, iden);
+            self.log.lock().unwrap().push(format!("{}:after", self.name));
+            result
+        }
+    }
+
+    #[test]
+    fn compose_runs_middlewares_outermost_first() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let outer = RecordingMiddleware { name: "outer", log: log.clone() };
+        let inner = RecordingMiddleware { name: "inner", log: log.clone() };
+        let middlewares: Vec<Box<MiddleWare<MemoryQueue>>> = vec![Box::new(outer), Box::new(inner)];
+
+        let queue = MemoryQueue::new(&MemoryConfig::default()).unwrap();
+        let core_log = log.clone();
+        let core: Box<Fn(EnqueuedJob, QueueIdentifier) -> RobinResult<()>> = Box::new(move |_enq_job, _iden| {
+            core_log.lock().unwrap().push("core".to_string());
+            Ok(())
+        });
+
+        let chain = compose(&middlewares, &queue, core);
+        chain(job(), QueueIdentifier::Main).unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["outer:before", "inner:before", "core", "inner:after", "outer:after"]
+        );
+    }
+
+    #[test]
+    fn retry_middleware_reenqueues_onto_retry_queue_on_failure() {
+        let queue = MemoryQueue::new(&MemoryConfig::default()).unwrap();
+        let middleware = RetryMiddleware::new(config(3));
+        let next: Box<Fn(EnqueuedJob, QueueIdentifier) -> RobinResult<()>> =
+            Box::new(|_enq_job, _iden| Err(Error::Internal("boom".to_string())));
+
+        middleware.call(job(), QueueIdentifier::Main, &queue, &*next).unwrap();
+
+        assert_eq!(queue.size(QueueIdentifier::Retry).unwrap(), 1);
+        assert_eq!(queue.size(QueueIdentifier::Main).unwrap(), 0);
+    }
+
+    #[test]
+    fn retry_middleware_gives_up_once_the_limit_is_reached() {
+        let queue = MemoryQueue::new(&MemoryConfig::default()).unwrap();
+        let middleware = RetryMiddleware::new(config(0));
+        let enq_job = EnqueuedJobBuilder::default()
+            .name("send_email".to_string())
+            .args("{}".to_string())
+            .retry_count(RetryCount::Count(1))
+            .build()
+            .unwrap();
+        let next: Box<Fn(EnqueuedJob, QueueIdentifier) -> RobinResult<()>> =
+            Box::new(|_enq_job, _iden| Err(Error::Internal("boom".to_string())));
+
+        let result = middleware.call(enq_job, QueueIdentifier::Main, &queue, &*next);
+
+        assert!(result.is_err());
+        assert_eq!(queue.size(QueueIdentifier::Retry).unwrap(), 0);
+    }
+}
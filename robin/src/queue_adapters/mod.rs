@@ -1,11 +1,29 @@
 /// Contains a queue implementation using Redis.
 pub mod redis_queue;
 
+/// Contains an in-memory queue implementation, useful for tests and local development.
+pub mod memory_queue;
+
+/// Contains the `MiddleWare` trait used to wrap job processing in cross-cutting behavior.
+pub mod middleware;
+
 use serde_json;
 use redis;
+use uuid::Uuid;
 use error::*;
 use config::Config;
+use std::fmt;
 use std::marker::Sized;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Convert `time` into a unix timestamp. Shared by every backend so `reap`/`heartbeat`/scheduled
+/// jobs all score and compare time the same way.
+pub(crate) fn unix_timestamp(time: SystemTime) -> RobinResult<u64> {
+    let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::Internal("System clock is before the Unix epoch".to_string()))?;
+    Ok(since_epoch.as_secs())
+}
 
 /// Trait that represents a backend that can be used to store jobs.
 pub trait JobQueue
@@ -21,8 +39,39 @@ where
     /// Push a job into the queue.
     fn enqueue(&self, enq_job: EnqueuedJob, iden: QueueIdentifier) -> RobinResult<()>;
 
-    /// Pull a job from the queue.
-    fn dequeue(&self, iden: QueueIdentifier) -> Result<EnqueuedJob, NoJobDequeued>;
+    /// Pull a job from the queue, moving it into `worker_id`'s processing list so it isn't lost
+    /// if the worker dies before it can `ack` the job.
+    fn dequeue(
+        &self,
+        timeout: &DequeueTimeout,
+        iden: QueueIdentifier,
+        worker_id: &str,
+    ) -> Result<EnqueuedJob, NoJobDequeued>;
+
+    /// Acknowledge that `enq_job` has finished processing, removing it from `worker_id`'s
+    /// processing list for good.
+    fn ack(&self, enq_job: &EnqueuedJob, iden: QueueIdentifier, worker_id: &str) -> RobinResult<()>;
+
+    /// Record that `worker_id` is still alive. Workers should call this regularly while they
+    /// run; `reap` uses the most recent heartbeat to decide whether a worker has crashed.
+    fn heartbeat(&self, worker_id: &str) -> RobinResult<()>;
+
+    /// Requeue jobs belonging to any worker whose heartbeat is older than `timeout`. Returns the
+    /// number of jobs that were moved back onto `iden`'s queue.
+    fn reap(&self, iden: QueueIdentifier, timeout: Duration) -> RobinResult<usize>;
+
+    /// Schedule a job to be moved onto the main queue at `when`, instead of enqueueing it right
+    /// away.
+    fn enqueue_at(&self, enq_job: EnqueuedJob, when: SystemTime) -> RobinResult<()>;
+
+    /// Schedule a job to be moved onto the main queue after `delay` has elapsed.
+    fn enqueue_in(&self, enq_job: EnqueuedJob, delay: Duration) -> RobinResult<()> {
+        self.enqueue_at(enq_job, SystemTime::now() + delay)
+    }
+
+    /// Move every scheduled job whose time has come onto the main queue. Returns the number of
+    /// jobs that were moved. Intended to be called periodically by a poller.
+    fn enqueue_due_jobs(&self, now: SystemTime) -> RobinResult<usize>;
 
     /// Delete all jobs from the queue.
     fn delete_all(&self, iden: QueueIdentifier) -> RobinResult<()>;
@@ -32,7 +81,7 @@ where
 }
 
 /// The number of times a job has been retried, if ever.
-#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RetryCount {
     /// The job has never been retried,
     NeverRetried,
@@ -59,15 +108,95 @@ impl RetryCount {
     }
 }
 
+/// A job whose concrete type, fields, and `perform` implementation travel through the queue as a
+/// boxed trait object serialized with `typetag`/`erased-serde`, instead of being hand-encoded
+/// into the `name`/`args` strings on `EnqueuedJob`. This is opt-in: implement it, annotate the
+/// impl with `#[typetag::serde]`, and hand an instance to `EnqueuedJob::from_job` to skip the
+/// args-encoding step and the name -> handler dispatch table entirely.
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize, Clone)]
+/// struct SendWelcomeEmail { user_id: u64 }
+///
+/// #[typetag::serde]
+/// impl Job for SendWelcomeEmail {
+///     fn perform(&self) -> RobinResult<()> {
+///         // ...
+///     }
+/// }
+/// ```
+#[typetag::serde(tag = "job_type")]
+pub trait Job: JobClone {
+    /// Run the job.
+    fn perform(&self) -> RobinResult<()>;
+}
+
+/// Lets `Box<dyn Job>` be cloned despite being a trait object. Blanket-implemented for every
+/// `Job` that is itself `Clone`; implementors never need to touch this directly.
+pub trait JobClone {
+    #[doc(hidden)]
+    fn clone_box(&self) -> Box<Job>;
+}
+
+impl<T> JobClone for T
+where
+    T: 'static + Job + Clone,
+{
+    fn clone_box(&self) -> Box<Job> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<Job> {
+    fn clone(&self) -> Box<Job> {
+        self.clone_box()
+    }
+}
+
+impl fmt::Debug for Box<Job> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Box<dyn Job>")
+    }
+}
+
 /// The data structure that gets serialized and put into Redis.
-#[derive(Deserialize, Serialize, Debug, Builder)]
+#[derive(Deserialize, Serialize, Debug, Clone, Builder)]
 pub struct EnqueuedJob {
+    /// A unique id identifying this particular job instance, used to tell two `EnqueuedJob`s
+    /// apart even when their other fields happen to match (e.g. two boxed `job`s of the same
+    /// type enqueued at the same retry count).
+    #[builder(default = "Uuid::new_v4().to_string()")]
+    id: String,
+
     name: String,
+
     args: String,
+
     retry_count: RetryCount,
+
+    /// An opt-in boxed job carrying its own fields and `perform` implementation, in place of the
+    /// `name`/`args` pair above. `None` for jobs using the classic name/args dispatch.
+    #[builder(default)]
+    job: Option<Box<Job>>,
 }
 
 impl EnqueuedJob {
+    /// Wrap `job` for enqueueing, bypassing the `name`/`args` encoding step entirely.
+    pub fn from_job(job: Box<Job>, retry_count: RetryCount) -> EnqueuedJob {
+        EnqueuedJob {
+            id: Uuid::new_v4().to_string(),
+            name: String::new(),
+            args: String::new(),
+            retry_count,
+            job: Some(job),
+        }
+    }
+
+    /// Get the id
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     /// Get the name
     pub fn name(&self) -> &str {
         &self.name
@@ -82,6 +211,33 @@ impl EnqueuedJob {
     pub fn retry_count(&self) -> &RetryCount {
         &self.retry_count
     }
+
+    /// Get the boxed job, if this `EnqueuedJob` was created from one instead of a `name`/`args`
+    /// pair.
+    pub fn job(&self) -> Option<&Job> {
+        self.job.as_ref().map(|job| job.as_ref())
+    }
+
+    /// Clone this job with its retry count incremented by one, e.g. before re-enqueueing it onto
+    /// `QueueIdentifier::Retry` after a failed attempt. Keeps the same `id`, since it's still the
+    /// same logical job.
+    pub fn retried(&self) -> EnqueuedJob {
+        EnqueuedJob {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            args: self.args.clone(),
+            retry_count: self.retry_count.increment(),
+            job: self.job.clone(),
+        }
+    }
+}
+
+impl PartialEq for EnqueuedJob {
+    /// Two `EnqueuedJob`s are the same job iff they share an `id`; `job` is never compared since
+    /// `Job` doesn't require `PartialEq`.
+    fn eq(&self, other: &EnqueuedJob) -> bool {
+        self.id == other.id
+    }
 }
 
 /// Reasons why attempting to dequeue a job didn't yield a job.
@@ -131,4 +287,10 @@ impl QueueIdentifier {
             QueueIdentifier::Retry => "retry".to_string(),
         }
     }
+
+    /// Every variant, used by `reap` to check whether a worker still has jobs in-flight on
+    /// *any* queue before forgetting it from the shared heartbeat/worker bookkeeping.
+    pub fn all() -> [QueueIdentifier; 2] {
+        [QueueIdentifier::Main, QueueIdentifier::Retry]
+    }
 }
@@ -1,22 +1,118 @@
 use error::*;
-use redis::{Client, Commands};
+use redis::{Commands, Script};
 use serde_json;
-use super::{DequeueTimeout, EnqueuedJob, JobQueue, NoJobDequeued, QueueIdentifier};
+use super::{unix_timestamp, DequeueTimeout, EnqueuedJob, JobQueue, NoJobDequeued, QueueIdentifier};
 use redis;
+use r2d2;
+use r2d2_redis::RedisConnectionManager;
+use rand::{thread_rng, Rng};
+use std::cmp;
 use std::fmt;
 use std::default::Default;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-/// A wrapper around an actual `redis::Connection`.
+/// Atomically moves every member of the schedule sorted set (`KEYS[1]`) due at or before
+/// `ARGV[1]` onto the main list (`KEYS[2]`), so a job is never both scheduled and enqueued, or
+/// dropped between the two calls.
+const ENQUEUE_DUE_JOBS_SCRIPT: &str = r#"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+for _, member in ipairs(due) do
+    redis.call('ZREM', KEYS[1], member)
+    redis.call('RPUSH', KEYS[2], member)
+end
+return #due
+"#;
+
+/// A connection pool backed `JobQueue`. Every call checks a connection out of the pool for the
+/// duration of the operation and returns it afterwards, so a single `RedisQueue` can safely be
+/// shared between many producer and worker threads.
 pub struct RedisQueue {
-    redis: redis::Connection,
+    pool: r2d2::Pool<RedisConnectionManager>,
     redis_url: String,
     key: String,
+    reconnect_base_delay: Duration,
+    reconnect_max_delay: Duration,
+    reconnect_max_attempts: u32,
 }
 
 impl RedisQueue {
     fn key(&self, iden: QueueIdentifier) -> String {
         format!("{}_{}", self.key, iden.redis_queue_name())
     }
+
+    /// The list a worker moves a job into while it works on it, so a crash doesn't lose the job.
+    fn processing_key(&self, iden: QueueIdentifier, worker_id: &str) -> String {
+        format!("{}_processing_{}", self.key(iden), worker_id)
+    }
+
+    /// The hash of `worker_id -> last_seen_unix_ts` used by `reap` to find dead workers.
+    fn heartbeat_key(&self) -> String {
+        format!("{}_heartbeats", self.key)
+    }
+
+    /// The set of every `worker_id` that has ever dequeued a job, so `reap` can find a worker's
+    /// orphaned processing list even if it crashed before calling `heartbeat` even once.
+    fn workers_key(&self) -> String {
+        format!("{}_workers", self.key)
+    }
+
+    /// The sorted set of jobs waiting to be enqueued at a future time, scored by the unix
+    /// timestamp they're due.
+    fn schedule_key(&self) -> String {
+        format!("{}_schedule", self.key)
+    }
+
+    fn connection(&self) -> RobinResult<r2d2::PooledConnection<RedisConnectionManager>> {
+        self.pool.get().map_err(Error::from)
+    }
+
+    /// The delay before the `attempt`th reconnect, as exponential backoff capped at
+    /// `reconnect_max_delay` with a little jitter so many workers don't retry in lockstep.
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let scale = 2u32.saturating_pow(attempt);
+        let backoff = self
+            .reconnect_base_delay
+            .checked_mul(scale)
+            .unwrap_or(self.reconnect_max_delay);
+        let capped = cmp::min(backoff, self.reconnect_max_delay);
+        let jitter = Duration::from_millis(thread_rng().gen_range(0, 50));
+
+        capped + jitter
+    }
+
+    /// Check out a fresh connection from the pool and run `op`, retrying with exponential
+    /// backoff if the error looks like a dropped/broken connection rather than an application
+    /// error. Gives up once `reconnect_max_attempts` has been reached.
+    fn with_reconnect<T, F>(&self, op: F) -> RobinResult<T>
+    where
+        F: Fn(&r2d2::PooledConnection<RedisConnectionManager>) -> redis::RedisResult<T>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let con = self.connection()?;
+
+            match op(&con) {
+                Ok(value) => return Ok(value),
+
+                Err(err) => {
+                    if !is_connection_error(&err) || attempt >= self.reconnect_max_attempts {
+                        return Err(Error::from(err));
+                    }
+
+                    thread::sleep(self.backoff_delay(attempt));
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// `true` if `err` looks like a dropped or otherwise broken connection, as opposed to a
+/// well-formed error response from Redis (a bad command, a type error, etc).
+fn is_connection_error(err: &redis::RedisError) -> bool {
+    err.kind() == redis::ErrorKind::IoError
 }
 
 /// The arguments required to create a new `RedisQueue`
@@ -24,6 +120,20 @@ impl RedisQueue {
 pub struct RedisConfig {
     pub url: String,
     pub namespace: String,
+
+    /// The number of connections kept open in the pool.
+    pub pool_size: u32,
+
+    /// The delay before the first reconnect attempt after a connection-level error. Doubles on
+    /// each subsequent attempt, up to `reconnect_max_delay`.
+    pub reconnect_base_delay: Duration,
+
+    /// The largest delay `reconnect_base_delay` is allowed to back off to.
+    pub reconnect_max_delay: Duration,
+
+    /// How many times to retry an operation after a connection-level error before giving up and
+    /// surfacing it.
+    pub reconnect_max_attempts: u32,
 }
 
 impl Default for RedisConfig {
@@ -31,68 +141,183 @@ impl Default for RedisConfig {
         RedisConfig {
             namespace: "robin_".to_string(),
             url: "redis://127.0.0.1/".to_string(),
+            pool_size: 10,
+            reconnect_base_delay: Duration::from_millis(100),
+            reconnect_max_delay: Duration::from_secs(30),
+            reconnect_max_attempts: 5,
         }
     }
 }
 
 impl JobQueue for RedisQueue {
-    type Init = RedisConfig;
+    type Config = RedisConfig;
 
     /// Create a new `RedisQueue` using the given config
     fn new(init: &RedisConfig) -> RobinResult<Self> {
-        let client = Client::open(init.url.as_ref())?;
+        let manager = RedisConnectionManager::new(init.url.as_ref())?;
 
-        let con = client.get_connection()?;
+        let pool = r2d2::Pool::builder()
+            .max_size(init.pool_size)
+            .build(manager)?;
 
         Ok(RedisQueue {
-            redis: con,
+            pool,
             redis_url: init.url.to_string(),
             key: init.namespace.to_string(),
+            reconnect_base_delay: init.reconnect_base_delay,
+            reconnect_max_delay: init.reconnect_max_delay,
+            reconnect_max_attempts: init.reconnect_max_attempts,
         })
     }
 
     /// Put a job into a queue
     fn enqueue(&self, enq_job: EnqueuedJob, iden: QueueIdentifier) -> RobinResult<()> {
         let data: String = json!(enq_job).to_string();
-        let _: () = self.redis.rpush(&self.key(iden), data)?;
-
-        Ok(())
+        self.with_reconnect(|con| con.rpush(&self.key(iden), &data))
     }
 
-    /// Pull a job out of the queue. This will block for `timeout` seconds if the queue is empty.
+    /// Pull a job out of the queue, atomically moving it into `worker_id`'s processing list.
+    /// This will block for `timeout` seconds if the queue is empty. The job stays in the
+    /// processing list until `ack` is called, so a worker that dies mid-job doesn't lose it.
+    ///
+    /// A connection-level error mid-`BRPOPLPUSH` reconnects and retries with the *remaining*
+    /// timeout, so a flaky connection can't turn one dequeue into several timeouts back to back.
     fn dequeue(
         &self,
         timeout: &DequeueTimeout,
         iden: QueueIdentifier,
+        worker_id: &str,
     ) -> Result<EnqueuedJob, NoJobDequeued> {
-        let timeout_in_seconds = timeout.0;
-        let bulk: Vec<redis::Value> = self.redis.blpop(&self.key(iden), timeout_in_seconds)?;
-
-        match bulk.get(1) {
-            Some(&redis::Value::Data(ref data)) => {
-                let data =
-                    String::from_utf8(data.to_vec()).expect("Didn't get valid UTF-8 from Redis");
-                serde_json::from_str(&data).map_err(NoJobDequeued::from)
-            }
+        let mut remaining = timeout.0;
+        let mut attempt = 0;
+
+        loop {
+            let con = self.connection()?;
+            let started = Instant::now();
+            let result: redis::RedisResult<Option<String>> =
+                con.brpoplpush(&self.key(iden), &self.processing_key(iden, worker_id), remaining);
+
+            match result {
+                Ok(Some(data)) => {
+                    // Seed the worker's membership and heartbeat right away, so `reap` can find
+                    // this job's processing list even if the worker crashes before its first
+                    // explicit `heartbeat()` call.
+                    let _: () = con.sadd(&self.workers_key(), worker_id)?;
+                    let _: () = con.hset(&self.heartbeat_key(), worker_id, unix_timestamp(SystemTime::now())?)?;
+
+                    return serde_json::from_str(&data).map_err(NoJobDequeued::from);
+                }
 
-            None => Err(NoJobDequeued::BecauseTimeout),
+                Ok(None) => return Err(NoJobDequeued::BecauseTimeout),
 
-            _ => Err(NoJobDequeued::from(Error::UnknownRedisError(
-                "List didn't contain what we were expecting".to_string(),
-            ))),
+                Err(err) => {
+                    if !is_connection_error(&err) || attempt >= self.reconnect_max_attempts {
+                        return Err(NoJobDequeued::from(Error::from(err)));
+                    }
+
+                    let backoff = self.backoff_delay(attempt);
+                    remaining = remaining
+                        .saturating_sub((started.elapsed() + backoff).as_secs() as usize);
+                    if remaining == 0 {
+                        return Err(NoJobDequeued::BecauseTimeout);
+                    }
+
+                    thread::sleep(backoff);
+                    attempt += 1;
+                }
+            }
         }
     }
 
+    /// Acknowledge that `enq_job` finished processing, removing it from `worker_id`'s
+    /// processing list.
+    fn ack(&self, enq_job: &EnqueuedJob, iden: QueueIdentifier, worker_id: &str) -> RobinResult<()> {
+        let data: String = json!(enq_job).to_string();
+        self.with_reconnect(|con| con.lrem(&self.processing_key(iden, worker_id), 1, &data))
+    }
+
+    /// Record that `worker_id` is still alive.
+    fn heartbeat(&self, worker_id: &str) -> RobinResult<()> {
+        let now = unix_timestamp(SystemTime::now())?;
+        self.with_reconnect(|con| con.hset(&self.heartbeat_key(), worker_id, now))
+    }
+
+    /// Requeue jobs belonging to any worker whose heartbeat is older than `timeout` (or that has
+    /// never heartbeated at all), moving them from that worker's processing list back onto
+    /// `iden`'s main list.
+    ///
+    /// The heartbeat and worker-membership bookkeeping is shared across every `QueueIdentifier`,
+    /// since a single worker can dequeue from both `Main` and `Retry`. So a worker is only
+    /// forgotten here once *every* queue's processing list for it is empty; otherwise a job
+    /// still in-flight on a queue nobody has called `reap` for yet would be orphaned the moment
+    /// its heartbeat/worker entry disappears.
+    fn reap(&self, iden: QueueIdentifier, timeout: Duration) -> RobinResult<usize> {
+        let now = unix_timestamp(SystemTime::now())?;
+
+        self.with_reconnect(|con| {
+            // Every worker that has ever dequeued a job is in this set, regardless of whether it
+            // survived long enough to call `heartbeat`, so a worker that crashed immediately
+            // after `dequeue` still has its processing list found and drained here.
+            let workers: Vec<String> = con.smembers(&self.workers_key())?;
+
+            let mut requeued = 0;
+            for worker_id in &workers {
+                let last_seen: Option<u64> = con.hget(&self.heartbeat_key(), worker_id)?;
+                if now.saturating_sub(last_seen.unwrap_or(0)) < timeout.as_secs() {
+                    continue;
+                }
+
+                let processing_key = self.processing_key(iden, worker_id);
+                loop {
+                    let moved: Option<String> = con.rpoplpush(&processing_key, &self.key(iden))?;
+                    match moved {
+                        Some(_) => requeued += 1,
+                        None => break,
+                    }
+                }
+
+                let still_in_flight = QueueIdentifier::all().iter().any(|other| {
+                    let len: u64 = con.llen(&self.processing_key(*other, worker_id)).unwrap_or(0);
+                    len > 0
+                });
+                if !still_in_flight {
+                    let _: () = con.hdel(&self.heartbeat_key(), worker_id)?;
+                    let _: () = con.srem(&self.workers_key(), worker_id)?;
+                }
+            }
+
+            Ok(requeued)
+        })
+    }
+
+    /// Schedule a job to be moved onto the main queue at `when`.
+    fn enqueue_at(&self, enq_job: EnqueuedJob, when: SystemTime) -> RobinResult<()> {
+        let score = unix_timestamp(when)?;
+        let data: String = json!(enq_job).to_string();
+        self.with_reconnect(|con| con.zadd(&self.schedule_key(), &data, score))
+    }
+
+    /// Move every scheduled job whose time has come onto the main queue.
+    fn enqueue_due_jobs(&self, now: SystemTime) -> RobinResult<usize> {
+        let now = unix_timestamp(now)?;
+
+        self.with_reconnect(|con| {
+            Script::new(ENQUEUE_DUE_JOBS_SCRIPT)
+                .key(self.schedule_key())
+                .key(self.key(QueueIdentifier::Main))
+                .arg(now)
+                .invoke(&**con)
+        })
+    }
+
     /// Delete everything in the queue.
     fn delete_all(&self, iden: QueueIdentifier) -> RobinResult<()> {
-        let _: () = self.redis.del(&self.key(iden))?;
-        Ok(())
+        self.with_reconnect(|con| con.del(&self.key(iden)))
     }
 
     /// The number of jobs in the queue.
     fn size(&self, iden: QueueIdentifier) -> RobinResult<usize> {
-        let size: usize = self.redis.llen(&self.key(iden)).map_err(Error::from)?;
-        Ok(size)
+        self.with_reconnect(|con| con.llen(&self.key(iden)))
     }
 }
 